@@ -3,19 +3,74 @@ mod reference;
 mod set;
 
 use base64;
-use chrono::{DateTime, NaiveDate, Utc};
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
 use serde::{
+    de::{self, Deserializer, MapAccess, SeqAccess, Visitor},
     ser::Serializer,
     ser::{SerializeMap, SerializeSeq},
-    Serialize,
+    Deserialize, Serialize,
 };
 use serde_json::Value;
 use std::borrow::Cow;
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
 
 pub use object::Object;
 pub use reference::Ref;
 pub use set::Set;
 
+/// Base64 alphabet/padding for the `@bytes` wire format. Defaults to
+/// `UrlSafeNoPad`, which is what FaunaDB expects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BytesEncoding {
+    UrlSafeNoPad,
+    UrlSafe,
+    Standard,
+    StandardNoPad,
+}
+
+impl Default for BytesEncoding {
+    fn default() -> Self {
+        BytesEncoding::UrlSafeNoPad
+    }
+}
+
+impl BytesEncoding {
+    fn config(self) -> base64::Config {
+        match self {
+            BytesEncoding::UrlSafeNoPad => base64::URL_SAFE_NO_PAD,
+            BytesEncoding::UrlSafe => base64::URL_SAFE,
+            BytesEncoding::Standard => base64::STANDARD,
+            BytesEncoding::StandardNoPad => base64::STANDARD_NO_PAD,
+        }
+    }
+}
+
+/// Encode `bytes` for the `@bytes` wire format using the given alphabet/padding.
+pub fn encode_bytes(bytes: &[u8], encoding: BytesEncoding) -> String {
+    base64::encode_config(bytes, encoding.config())
+}
+
+/// Decode an `@bytes` payload, tolerating any alphabet/padding combination.
+fn decode_bytes(raw: &str) -> Result<Vec<u8>, base64::DecodeError> {
+    let configs = [
+        BytesEncoding::UrlSafeNoPad.config(),
+        BytesEncoding::UrlSafe.config(),
+        BytesEncoding::Standard.config(),
+        BytesEncoding::StandardNoPad.config(),
+    ];
+
+    let mut last_err = None;
+    for config in configs {
+        match base64::decode_config(raw, config) {
+            Ok(bytes) => return Ok(bytes),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.expect("configs is non-empty"))
+}
+
 #[derive(Debug, Clone)]
 pub enum Expr<'a> {
     String(Cow<'a, str>),
@@ -34,26 +89,6 @@ pub enum Expr<'a> {
     Timestamp(DateTime<Utc>),
 }
 
-impl<'a> From<Value> for Expr<'a> {
-    fn from(val: Value) -> Self {
-        match val {
-            Value::Null => Expr::Null,
-            Value::Bool(b) => Expr::from(b),
-            Value::Number(num) => {
-                if num.is_i64() {
-                    Expr::from(num.as_i64().unwrap())
-                } else if num.is_u64() {
-                    Expr::from(num.as_u64().unwrap())
-                } else {
-                    Expr::from(num.as_f64().unwrap())
-                }
-            }
-            Value::String(s) => Expr::from(s),
-            _ => unimplemented!(),
-        }
-    }
-}
-
 impl<'a> Serialize for Expr<'a> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -74,7 +109,7 @@ impl<'a> Serialize for Expr<'a> {
             }
             Expr::Bytes(b) => {
                 let mut map = serializer.serialize_map(Some(1))?;
-                map.serialize_entry("@bytes", &base64::encode(b))?;
+                map.serialize_entry("@bytes", &encode_bytes(b, BytesEncoding::default()))?;
                 map.end()
             }
             Expr::Date(d) => {
@@ -101,13 +136,154 @@ impl<'a> Serialize for Expr<'a> {
             }
             Expr::Timestamp(dt) => {
                 let mut map = serializer.serialize_map(Some(1))?;
-                map.serialize_entry("@ts", &dt.to_rfc3339())?;
+                map.serialize_entry("@ts", &dt.to_rfc3339_opts(SecondsFormat::Nanos, true))?;
                 map.end()
             }
         }
     }
 }
 
+/// Integer JSON numbers decode to `Expr::Int`/`Expr::UInt` and all other numbers
+/// to `Expr::Double`; there is no wire distinction
+/// between `Expr::Float` and `Expr::Double`, so a serialized `Expr::Float` comes
+/// back as `Expr::Double` with the same value.
+///
+/// Always allocates owned strings (no `#[serde(borrow)]`), so this works with
+/// `DeserializeOwned` consumers such as `serde_json::from_value`/`from_reader`.
+impl<'de, 'a> Deserialize<'de> for Expr<'a> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(ExprVisitor(PhantomData))
+    }
+}
+
+struct ExprVisitor<'a>(PhantomData<&'a ()>);
+
+impl<'de, 'a> Visitor<'de> for ExprVisitor<'a> {
+    type Value = Expr<'a>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a FaunaDB value")
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<Self::Value, E> {
+        Ok(Expr::Boolean(v))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E> {
+        Ok(Expr::Int(v))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E> {
+        Ok(Expr::UInt(v))
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<Self::Value, E> {
+        Ok(Expr::Double(v))
+    }
+
+    fn visit_unit<E>(self) -> Result<Self::Value, E> {
+        Ok(Expr::Null)
+    }
+
+    fn visit_none<E>(self) -> Result<Self::Value, E> {
+        Ok(Expr::Null)
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E> {
+        Ok(Expr::String(Cow::Owned(v.to_owned())))
+    }
+
+    fn visit_string<E>(self, v: String) -> Result<Self::Value, E> {
+        Ok(Expr::String(Cow::Owned(v)))
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut elements = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(element) = seq.next_element()? {
+            elements.push(element);
+        }
+        Ok(Expr::Array(elements))
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let key: String = match map.next_key()? {
+            Some(key) => key,
+            None => return Err(de::Error::custom("expected a tagged FaunaDB value")),
+        };
+
+        match key.as_str() {
+            "@ref" => {
+                let r: Ref<'a> = map.next_value()?;
+                Ok(Expr::Ref(r))
+            }
+            "@ts" => {
+                let raw: String = map.next_value()?;
+                let dt = DateTime::parse_from_rfc3339(&raw)
+                    .map_err(de::Error::custom)?
+                    .with_timezone(&Utc);
+                Ok(Expr::Timestamp(dt))
+            }
+            "@date" => {
+                let raw: String = map.next_value()?;
+                let date =
+                    NaiveDate::parse_from_str(&raw, "%Y-%m-%d").map_err(de::Error::custom)?;
+                Ok(Expr::Date(date))
+            }
+            "@bytes" => {
+                let raw: String = map.next_value()?;
+                let bytes = decode_bytes(&raw).map_err(de::Error::custom)?;
+                Ok(Expr::Bytes(Cow::Owned(bytes)))
+            }
+            "@set" => {
+                let set: Set<'a> = map.next_value()?;
+                Ok(Expr::Set(Box::new(set)))
+            }
+            "object" => {
+                let object: Object<'a> = map.next_value()?;
+                Ok(Expr::Object(object))
+            }
+            other => Err(de::Error::unknown_field(
+                other,
+                &["@ref", "@ts", "@date", "@bytes", "@set", "object"],
+            )),
+        }
+    }
+}
+
+impl<'a> From<Value> for Expr<'a> {
+    fn from(val: Value) -> Self {
+        match val {
+            Value::Null => Expr::Null,
+            Value::Bool(b) => Expr::from(b),
+            Value::Number(num) => {
+                if num.is_i64() {
+                    Expr::from(num.as_i64().unwrap())
+                } else if num.is_u64() {
+                    Expr::from(num.as_u64().unwrap())
+                } else {
+                    Expr::from(num.as_f64().unwrap())
+                }
+            }
+            Value::String(s) => Expr::from(s),
+            // Arrays and objects are not converted here; use
+            // `serde_json::from_value` (via the `Deserialize` impl above) to
+            // decode those instead of this scalar-only conversion.
+            _ => unimplemented!(
+                "Expr::from(Value) only supports scalars; use serde_json::from_value for arrays/objects"
+            ),
+        }
+    }
+}
+
 impl<'a> From<&'a str> for Expr<'a> {
     fn from(s: &'a str) -> Expr<'a> {
         Expr::String(Cow::from(s))
@@ -249,9 +425,57 @@ impl<'a> From<DateTime<Utc>> for Expr<'a> {
     }
 }
 
+impl<'a> Expr<'a> {
+    /// Serialize to deterministic bytes (sorted object keys, `-0.0` folded into `0.0`), suitable for hashing as a cache key.
+    pub fn to_canonical_bytes(&self) -> serde_json::Result<Vec<u8>> {
+        let value = canonicalize(serde_json::to_value(self)?);
+        serde_json::to_vec(&value)
+    }
+
+    /// Serialize into `writer` without allocating an intermediate `String`.
+    pub fn write_to<W: io::Write>(&self, writer: &mut W) -> serde_json::Result<()> {
+        serde_json::to_writer(writer, self)
+    }
+
+    /// Serialize into `buf`, clearing and reusing its existing capacity.
+    pub fn write_to_buf(&self, buf: &mut Vec<u8>) -> serde_json::Result<()> {
+        buf.clear();
+        self.write_to(buf)
+    }
+}
+
+/// Rebuild `value`'s objects with their keys in sorted order and fold `-0.0` into `0.0`.
+fn canonicalize(value: Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<(String, Value)> =
+                map.into_iter().map(|(k, v)| (k, canonicalize(v))).collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            Value::Object(entries.into_iter().collect())
+        }
+        Value::Array(items) => Value::Array(items.into_iter().map(canonicalize).collect()),
+        Value::Number(n) => Value::Number(canonicalize_number(n)),
+        other => other,
+    }
+}
+
+/// `0.0 == -0.0` but they serialize to different bytes; normalize to `0.0` so
+/// two `Expr`s that are equal in value always produce identical canonical output.
+fn canonicalize_number(n: serde_json::Number) -> serde_json::Number {
+    if n.is_f64() {
+        if let Some(f) = n.as_f64() {
+            if f == 0.0 {
+                return serde_json::Number::from_f64(0.0).expect("0.0 is finite");
+            }
+        }
+    }
+    n
+}
+
 #[cfg(test)]
 mod tests {
     use crate::prelude::*;
+    use super::{decode_bytes, encode_bytes, BytesEncoding};
     use chrono::{DateTime, NaiveDate, Utc};
     use serde_json::{self, json};
 
@@ -365,7 +589,7 @@ mod tests {
         let expr = Expr::from(bytes.as_slice());
         let serialized = serde_json::to_string(&expr).unwrap();
 
-        assert_eq!("{\"@bytes\":\"AQIDBA==\"}", serialized)
+        assert_eq!("{\"@bytes\":\"AQIDBA\"}", serialized)
     }
 
     #[test]
@@ -463,8 +687,278 @@ mod tests {
         let expr = Expr::from(dt);
         let serialized = serde_json::to_value(&expr).unwrap();
 
-        let expected = json!({ "@ts": "2019-05-26T16:20:00+00:00" });
+        let expected = json!({ "@ts": "2019-05-26T16:20:00.000000000Z" });
 
         assert_eq!(expected, serialized);
     }
+
+    #[test]
+    fn test_timestamp_fraction_matrix() {
+        let cases = [
+            ("2019-05-26T16:20:00Z", "2019-05-26T16:20:00.000000000Z"),
+            (
+                "2019-05-26T16:20:00.123Z",
+                "2019-05-26T16:20:00.123000000Z",
+            ),
+            (
+                "2019-05-26T16:20:00.123456Z",
+                "2019-05-26T16:20:00.123456000Z",
+            ),
+            (
+                "2019-05-26T16:20:00.123456789Z",
+                "2019-05-26T16:20:00.123456789Z",
+            ),
+        ];
+
+        for (input, expected) in &cases {
+            let dt = DateTime::parse_from_rfc3339(input)
+                .unwrap()
+                .with_timezone(&Utc);
+            let expr = Expr::from(dt);
+            let serialized = serde_json::to_value(&expr).unwrap();
+
+            assert_eq!(json!({ "@ts": expected }), serialized);
+
+            let decoded: Expr = serde_json::from_value(serialized.clone()).unwrap();
+            let reserialized = serde_json::to_value(&decoded).unwrap();
+
+            assert_eq!(serialized, reserialized);
+        }
+    }
+
+    fn round_trip(expr: Expr) {
+        let value = serde_json::to_value(&expr).unwrap();
+        let decoded: Expr = serde_json::from_value(value.clone()).unwrap();
+        let reencoded = serde_json::to_value(&decoded).unwrap();
+
+        assert_eq!(value, reencoded);
+    }
+
+    #[test]
+    fn test_round_trip_string() {
+        round_trip(Expr::from("cat"));
+    }
+
+    #[test]
+    fn test_round_trip_double() {
+        round_trip(Expr::from(4.12f64));
+    }
+
+    #[test]
+    fn test_round_trip_float_collapses_to_double() {
+        let expr = Expr::from(4.12f32);
+        let value = serde_json::to_value(&expr).unwrap();
+        let decoded: Expr = serde_json::from_value(value).unwrap();
+
+        assert!(matches!(decoded, Expr::Double(_)));
+        assert_eq!(
+            serde_json::to_value(&expr).unwrap(),
+            serde_json::to_value(&decoded).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_round_trip_int() {
+        round_trip(Expr::from(4i64));
+    }
+
+    #[test]
+    fn test_round_trip_uint() {
+        round_trip(Expr::from(4u64));
+    }
+
+    #[test]
+    fn test_round_trip_boolean() {
+        round_trip(Expr::from(true));
+    }
+
+    #[test]
+    fn test_round_trip_null() {
+        round_trip(Expr::Null);
+    }
+
+    #[test]
+    fn test_round_trip_object() {
+        let mut object = Object::new();
+        object.insert("foo", "bar");
+        object.insert("lol", false);
+
+        round_trip(Expr::from(object));
+    }
+
+    #[test]
+    fn test_round_trip_bytes() {
+        let bytes = vec![0x1, 0x2, 0x3, 0x4];
+        round_trip(Expr::from(bytes.as_slice()));
+    }
+
+    #[test]
+    fn test_round_trip_date() {
+        round_trip(Expr::from(NaiveDate::from_ymd(2001, 5, 31)));
+    }
+
+    #[test]
+    fn test_round_trip_ref() {
+        round_trip(Expr::from(Ref::new("foo", Ref::class("test"))));
+    }
+
+    #[test]
+    fn test_round_trip_array() {
+        let array = vec![Expr::from(1), Expr::from("test")];
+        round_trip(Expr::from(array));
+    }
+
+    #[test]
+    fn test_round_trip_set() {
+        let set = Set::matching(Ref::index("cats_age"), 8);
+        round_trip(Expr::from(set));
+    }
+
+    #[test]
+    fn test_write_to_matches_to_string() {
+        let mut object = Object::new();
+        object.insert("foo", "bar");
+        object.insert("lol", false);
+
+        let expr = Expr::from(object);
+
+        let mut buf = Vec::new();
+        expr.write_to(&mut buf).unwrap();
+
+        assert_eq!(serde_json::to_string(&expr).unwrap().into_bytes(), buf);
+    }
+
+    #[test]
+    fn test_write_to_buf_reuses_buffer_across_queries() {
+        let mut buf = Vec::with_capacity(4);
+
+        Expr::from("cat").write_to_buf(&mut buf).unwrap();
+        assert_eq!(b"\"cat\"".to_vec(), buf);
+        let grown_capacity = buf.capacity();
+
+        Expr::from(1i64).write_to_buf(&mut buf).unwrap();
+        assert_eq!(b"1".to_vec(), buf);
+        // clear() keeps the allocation around, so capacity never shrinks back down
+        assert!(buf.capacity() >= grown_capacity);
+    }
+
+    #[test]
+    fn test_canonical_bytes_ignore_insert_order() {
+        let mut forward = Object::new();
+        forward.insert("foo", "bar");
+        forward.insert("lol", false);
+
+        let mut reverse = Object::new();
+        reverse.insert("lol", false);
+        reverse.insert("foo", "bar");
+
+        let forward_bytes = Expr::from(forward).to_canonical_bytes().unwrap();
+        let reverse_bytes = Expr::from(reverse).to_canonical_bytes().unwrap();
+
+        assert_eq!(forward_bytes, reverse_bytes);
+        assert_eq!(
+            b"{\"object\":{\"foo\":\"bar\",\"lol\":false}}".to_vec(),
+            forward_bytes
+        );
+    }
+
+    #[test]
+    fn test_canonical_bytes_sorts_nested_objects() {
+        let mut inner_forward = Object::new();
+        inner_forward.insert("b", 1);
+        inner_forward.insert("a", 2);
+
+        let mut outer_forward = Object::new();
+        outer_forward.insert("z", inner_forward);
+        outer_forward.insert("y", 3);
+
+        let mut inner_reverse = Object::new();
+        inner_reverse.insert("a", 2);
+        inner_reverse.insert("b", 1);
+
+        let mut outer_reverse = Object::new();
+        outer_reverse.insert("y", 3);
+        outer_reverse.insert("z", inner_reverse);
+
+        let forward_bytes = Expr::from(outer_forward).to_canonical_bytes().unwrap();
+        let reverse_bytes = Expr::from(outer_reverse).to_canonical_bytes().unwrap();
+
+        assert_eq!(forward_bytes, reverse_bytes);
+    }
+
+    #[test]
+    fn test_canonical_bytes_folds_negative_zero() {
+        let positive = Expr::from(0.0f64).to_canonical_bytes().unwrap();
+        let negative = Expr::from(-0.0f64).to_canonical_bytes().unwrap();
+
+        assert_eq!(positive, negative);
+        assert_eq!(b"0.0".to_vec(), positive);
+    }
+
+    #[test]
+    fn test_bytes_url_safe_round_trip() {
+        let bytes = vec![0xfb, 0xff, 0xbf, 0x00, 0x10, 0x83];
+        let expr = Expr::from(bytes.as_slice());
+        let serialized = serde_json::to_value(&expr).unwrap();
+
+        // these bytes would need the `+`/`/` alphabet under standard base64
+        assert_eq!(json!({ "@bytes": "-_-_ABCD" }), serialized);
+
+        let decoded: Expr = serde_json::from_value(serialized).unwrap();
+        match decoded {
+            Expr::Bytes(decoded_bytes) => assert_eq!(bytes, decoded_bytes.into_owned()),
+            other => panic!("expected Expr::Bytes, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_bytes_decode_tolerates_alternate_alphabets() {
+        let six_bytes = vec![0xfb, 0xff, 0xbf, 0x00, 0x10, 0x83];
+        let four_bytes = vec![0x1, 0x2, 0x3, 0x4];
+
+        let cases = vec![
+            ("-_-_ABCD", six_bytes.clone()),
+            ("+/+/ABCD", six_bytes),
+            ("AQIDBA==", four_bytes),
+        ];
+
+        for (encoded, expected) in cases {
+            let value = json!({ "@bytes": encoded });
+            let decoded: Expr = serde_json::from_value(value).unwrap();
+            match decoded {
+                Expr::Bytes(decoded_bytes) => assert_eq!(expected, decoded_bytes.into_owned()),
+                other => panic!("expected Expr::Bytes, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_bytes_encode_decode_encode_cycle() {
+        let samples: Vec<Vec<u8>> = vec![
+            vec![],
+            vec![0x00],
+            vec![0x1, 0x2, 0x3, 0x4],
+            vec![0xff; 32],
+            (0..=255u8).collect(),
+        ];
+
+        for bytes in samples {
+            let first = encode_bytes(&bytes, BytesEncoding::default());
+            let decoded = decode_bytes(&first).unwrap();
+            let second = encode_bytes(&decoded, BytesEncoding::default());
+
+            assert_eq!(bytes, decoded);
+            assert_eq!(first, second);
+        }
+    }
+
+    #[test]
+    fn test_round_trip_timestamp() {
+        let dt_str = "2019-05-26T16:20:00Z";
+        let dt = DateTime::parse_from_rfc3339(dt_str)
+            .unwrap()
+            .with_timezone(&Utc);
+
+        round_trip(Expr::from(dt));
+    }
 }